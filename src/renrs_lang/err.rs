@@ -11,6 +11,26 @@ pub enum CompilationErrKind {
      */
     InvalidNumber, // Number
 
+    /**
+     * A triple-quoted string (`"""..."""`) was opened but EOF was reached
+     * before the matching closing delimiter.
+     */
+    UnterminatedString,
+
+    /**
+     * An unknown `\x` escape sequence, or a malformed `\u{...}` escape, was
+     * found inside a string.
+     */
+    InvalidEscape,
+
+    /**
+     * A number was immediately followed by a unit-looking suffix, but the
+     * numeric part itself was already invalid (e.g. `1.2.3s`). A valid number
+     * followed by a suffix that isn't `ms`/`s`/`m` is not an error -- it just
+     * stays a plain number and the letters lex separately.
+     */
+    InvalidDuration,
+
     /** Code should not be read */
     Unreachable,
 }
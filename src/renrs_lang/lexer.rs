@@ -3,7 +3,7 @@ use super::err::{CompilationErr, CompilationErrKind};
 /**
  * # Lexer
  * -- implementation from [Mohit Karekar](https://mohitkarekar.com/posts/pl/lexer/)
- *  
+ *
  * what it does: it breaks down source code input and makes tokens out of them
  *
  * ## We have the following fields in this struct
@@ -21,9 +21,177 @@ use super::err::{CompilationErr, CompilationErrKind};
  */
 #[derive(Debug)]
 pub struct Lexer {
-    input: std::iter::Peekable<std::vec::IntoIter<char>>,
+    cursor: Cursor,
     position: Pos,
     ch: Option<char>,
+    mode_stack: Vec<LexerMode>,
+    /** Whether the next token begins a new statement -- see [`LexerMode::Dialogue`]. */
+    at_statement_start: bool,
+}
+
+/**
+ * # Cursor
+ *
+ * A backwards-seekable character stream over the source text. It replaces
+ * the lexer's old `Peekable<IntoIter<char>>`, which could only look one
+ * character ahead and never go back -- brittle for cases where the lexer
+ * has already consumed a character it needs to reinterpret, e.g. a `.`
+ * that turns out not to start a number, or disambiguating `1.` followed by
+ * an identifier.
+ *
+ * `history` holds every character of the input (known up front, same as
+ * before); `line_lengths` records the length of each completed line so
+ * that [`line_and_column`](Cursor::line_and_column) can recover the right
+ * column after rewinding across a newline. `max_pos` is the furthest index
+ * the cursor has ever reached -- the "live" edge of the stream -- and
+ * `offset` is how far the cursor currently trails behind it: `0` means
+ * we're at the live edge and `next()` advances normally; after
+ * [`seek_back`](Cursor::seek_back), `next()`/`peek()` replay characters
+ * already in `history` instead of consuming new ones.
+ */
+#[derive(Debug)]
+struct Cursor {
+    history: Vec<char>,
+    line_lengths: Vec<usize>,
+    max_pos: usize,
+    offset: usize,
+}
+
+impl Cursor {
+    fn new(input: &str) -> Self {
+        let history: Vec<char> = input.chars().collect();
+        let mut line_lengths = Vec::new();
+        let mut line_len = 0;
+        for &ch in &history {
+            if ch == '\n' {
+                line_lengths.push(line_len);
+                line_len = 0;
+            } else {
+                line_len += 1;
+            }
+        }
+        Cursor {
+            history,
+            line_lengths,
+            max_pos: 0,
+            offset: 0,
+        }
+    }
+
+    fn pos(&self) -> usize {
+        self.max_pos - self.offset
+    }
+
+    /** Reads and consumes the next character, advancing the cursor. */
+    fn next(&mut self) -> Option<char> {
+        let ch = self.history.get(self.pos()).copied()?;
+        if self.offset > 0 {
+            self.offset -= 1;
+        } else {
+            self.max_pos += 1;
+        }
+        Some(ch)
+    }
+
+    /** Looks at the next character without consuming it. O(1): a plain index into `history`. */
+    fn peek(&self) -> Option<&char> {
+        self.history.get(self.pos())
+    }
+
+    /** Looks `ahead` characters past the next one, without consuming anything. */
+    fn peek_at(&self, ahead: usize) -> Option<&char> {
+        self.history.get(self.pos() + ahead)
+    }
+
+    /** Rewinds by `n` characters so the next `n` reads replay what was already seen. */
+    fn seek_back(&mut self, n: usize) {
+        self.offset = (self.offset + n).min(self.max_pos);
+    }
+
+    /** Replays forward by `n` characters after [`seek_back`](Cursor::seek_back), back towards the live edge. */
+    fn seek_forward(&mut self, n: usize) {
+        self.offset = self.offset.saturating_sub(n);
+    }
+
+    /** The 0-based `(line, column)` of the next character, derived from `line_lengths`. */
+    fn line_and_column(&self) -> (usize, usize) {
+        let mut chars_before = 0;
+        for (line, &len) in self.line_lengths.iter().enumerate() {
+            let line_end = chars_before + len;
+            if self.pos() <= line_end {
+                return (line, self.pos() - chars_before);
+            }
+            chars_before = line_end + 1;
+        }
+        (self.line_lengths.len(), self.pos() - chars_before)
+    }
+}
+
+/**
+ * # LexerMode
+ *
+ * A visual-novel script is really two languages sharing one file: a `Code`
+ * region (`c = Character "Crab"`) and free-form `Dialogue` text where
+ * punctuation should stay literal. The [`Lexer`] keeps a stack of these
+ * modes, modeled on the flexer group mechanism, and dispatches `next()` to
+ * whichever mode sits on top via [`push_mode`](Lexer::push_mode) /
+ * [`pop_mode`](Lexer::pop_mode).
+ *
+ * A mode's own rules are tried first, in definition order; if none match,
+ * its [`parent`](LexerMode::parent) mode's rules are tried next, so a child
+ * mode only needs to describe how it differs from `Code`.
+ *
+ * `Dialogue` is entered automatically: a statement that opens with a bare
+ * identifier directly followed by a quote (`c "What a fine day"`) reads as
+ * dialogue for the rest of that line, then returns to `Code`. An identifier
+ * that isn't at the start of a statement (the `Character` in
+ * `c = Character "Crab"`) never triggers it, so ordinary string arguments
+ * keep lexing as `Code`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexerMode {
+    /** Operators, identifiers, numbers, strings -- the default mode. */
+    Code,
+    /** Free-form prose up to a newline or `;`, lexed as a single [`Token::String`]. */
+    Dialogue,
+}
+
+/** A single tokenizing rule: tries to consume `self.ch`, returning `None` to defer to the next rule. */
+type Rule = fn(&mut Lexer, &Pos) -> Option<Result<Token, CompilationErr>>;
+
+impl LexerMode {
+    fn own_rules(&self) -> &'static [Rule] {
+        match self {
+            LexerMode::Code => &[
+                Lexer::rule_whitespace,
+                Lexer::rule_line_comment,
+                Lexer::rule_block_comment,
+                Lexer::rule_operators,
+                Lexer::rule_string,
+                Lexer::rule_eol,
+                Lexer::rule_ident,
+                Lexer::rule_dot,
+                Lexer::rule_num,
+            ],
+            LexerMode::Dialogue => &[Lexer::rule_dialogue_text],
+        }
+    }
+
+    /** The mode whose rules apply when this mode's own rules don't match. */
+    fn parent(&self) -> Option<LexerMode> {
+        match self {
+            LexerMode::Code => None,
+            LexerMode::Dialogue => Some(LexerMode::Code),
+        }
+    }
+
+    fn rules(&self) -> Vec<Rule> {
+        let mut rules = self.own_rules().to_vec();
+        if let Some(parent) = self.parent() {
+            rules.extend(parent.rules());
+        }
+        rules
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -48,6 +216,32 @@ impl Default for Pos {
 const INTERNAL_ERR_MSG: &str = r#"Reached unreachable. This is an error within renrs-lang itself.
 Report this bug on Github: https://github.com/FaCsaba/renrs-lang/issues"#;
 
+impl std::fmt::Display for Pos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/**
+ * # Span
+ *
+ * The extent of a token: where it `start`s and where it `end`s. A single
+ * `Pos` is enough for a one-character token, but identifiers, numbers and
+ * strings span several characters, and error messages want to underline
+ * the whole range rather than just its first character.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
 impl Pos {
     pub fn advance(&mut self, new_line: bool) -> Result<&Self, CompilationErr> {
         if new_line {
@@ -106,14 +300,40 @@ pub enum Token {
 
     String(Vec<char>),
 
+    /** A `#`/`//` line comment, marker included, up to (not including) the newline. */
+    LineComment(Vec<char>),
+    /** A `/* ... */` block comment, including both delimiters. */
+    BlockComment(Vec<char>),
+    /** A contiguous run of spaces/tabs. */
+    Whitespace(Vec<char>),
+
+    /** A number immediately followed by a time unit, e.g. `1s`, `250ms`, `2m`. */
+    Duration { value: Vec<char>, unit: DurationUnit },
+
     EndOfLine,
     Invalid(char),
 }
 
+/**
+ * # DurationUnit
+ *
+ * The time unit of a [`Token::Duration`], as used by `wait`/animation timing
+ * commands like `wait 1s`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurationUnit {
+    /** Milliseconds, e.g. `250ms`. */
+    Ms,
+    /** Seconds, e.g. `1s`. */
+    S,
+    /** Minutes, e.g. `2m`. */
+    M,
+}
+
 impl Lexer {
     pub fn new(input: &str) -> Self {
         Lexer {
-            input: input.chars().collect::<Vec<char>>().into_iter().peekable(),
+            cursor: Cursor::new(input),
             position: Pos {
                 file: None,
                 line: 0,
@@ -121,12 +341,62 @@ impl Lexer {
                 raw: None,
             },
             ch: None,
+            mode_stack: vec![LexerMode::Code],
+            at_statement_start: true,
         }
     }
 
+    /** Pushes a new mode on top of the stack; it is tried before its parent's rules until popped. */
+    pub fn push_mode(&mut self, mode: LexerMode) {
+        self.mode_stack.push(mode);
+    }
+
+    /** Pops back to the previous mode. The bottom `Code` mode is never popped. */
+    pub fn pop_mode(&mut self) -> Option<LexerMode> {
+        if self.mode_stack.len() > 1 {
+            self.mode_stack.pop()
+        } else {
+            None
+        }
+    }
+
+    fn current_mode(&self) -> LexerMode {
+        *self.mode_stack.last().unwrap_or(&LexerMode::Code)
+    }
+
+    /**
+     * Rewinds by `n` characters, e.g. after a speculative read that turned
+     * out not to apply (a `.` that doesn't start a number, or disambiguating
+     * `1.` followed by an identifier rather than a field access).
+     */
+    pub fn seek_back(&mut self, n: usize) {
+        self.cursor.seek_back(n);
+        self.resync_position();
+    }
+
+    /**
+     * Replays forward by `n` characters after [`seek_back`](Lexer::seek_back), e.g. to
+     * give back only part of a speculative read once more of it turns out to be needed
+     * after all. No current rule needs partial give-back -- they rewind everything they
+     * speculatively read, as [`read_duration_suffix`](Lexer::read_duration_suffix) does --
+     * but the pair is kept symmetric with [`Cursor::seek_forward`], which this wraps.
+     */
+    #[allow(dead_code)]
+    pub fn seek_forward(&mut self, n: usize) {
+        self.cursor.seek_forward(n);
+        self.resync_position();
+    }
+
+    fn resync_position(&mut self) {
+        let (line, column) = self.cursor.line_and_column();
+        self.position.line = line;
+        self.position.column = column;
+        self.ch = None;
+    }
+
     fn read_char(&mut self) -> Option<char> {
         self.position.advance(self.ch == Some('\n')).unwrap();
-        self.ch = self.input.next();
+        self.ch = self.cursor.next();
         self.ch
     }
 
@@ -151,7 +421,7 @@ impl Lexer {
             message: format!("{}", INTERNAL_ERR_MSG.to_string()),
         })?];
 
-        while Self::is_alphanumeric(self.input.peek()) {
+        while Self::is_alphanumeric(self.cursor.peek()) {
             if let Some(c) = self.read_char() {
                 ident.push(c);
             }
@@ -164,12 +434,13 @@ impl Lexer {
     }
 
     fn read_num(&mut self) -> Result<Vec<char>, CompilationErr> {
+        let start = self.position.clone();
         let mut num = vec![self.ch.ok_or(CompilationErr {
             kind: CompilationErrKind::Unreachable,
             message: format!("{}", INTERNAL_ERR_MSG),
         })?];
 
-        while Self::is_num_char(self.input.peek()) {
+        while Self::is_num_char(self.cursor.peek()) {
             if let Some(c) = self.read_char() {
                 num.push(c);
             }
@@ -179,91 +450,384 @@ impl Lexer {
             return Err(CompilationErr {
                 kind: CompilationErrKind::InvalidNumber,
                 message: format!(
-                    "Invalid number at: {}:{}:{}",
+                    "Invalid number at: {}:{}",
                     self.position.file.as_ref().unwrap_or(&String::from("")),
-                    self.position.line,
-                    self.position.column
+                    Span {
+                        start,
+                        end: self.position.clone()
+                    }
                 ),
             });
         }
         Ok(num)
     }
 
-    fn read_string(&mut self) -> Result<Vec<char>, CompilationErr> {
-        let mut string = vec![];
-        while self.input.peek() != None
-            && self.input.peek() != Some(&'"')
-            && self.input.peek() != Some(&'\'')
-            && self.input.peek() != Some(&'\n')
-            && self.input.peek() != Some(&';')
-        {
-            string.push(self.read_char().unwrap()) // Unreachable
+    /**
+     * Reads the body of a string opened by `delimiter` (`self.ch` is already the opening
+     * delimiter). Doubling the delimiter to three in a row (`"""..."""`, ```` ```...``` ````)
+     * opens a triple-quoted string that crosses newlines and only ends at the matching triple
+     * delimiter; a plain delimiter instead closes (or, for backwards compatibility, implicitly
+     * ends at an unescaped newline/`;`, or at EOF).
+     */
+    fn read_string(&mut self, delimiter: char, start: &Pos) -> Result<Vec<char>, CompilationErr> {
+        let triple =
+            self.cursor.peek() == Some(&delimiter) && self.cursor.peek_at(1) == Some(&delimiter);
+        if triple {
+            self.read_char();
+            self.read_char();
         }
 
-        if self.input.peek() == Some(&'"') {
-            self.read_char();
+        let mut string = vec![];
+        loop {
+            match self.cursor.peek().copied() {
+                None if triple => {
+                    return Err(CompilationErr {
+                        kind: CompilationErrKind::UnterminatedString,
+                        message: format!(
+                            "Unterminated string starting at: {}:{}",
+                            self.position.file.as_ref().unwrap_or(&String::from("")),
+                            start
+                        ),
+                    })
+                }
+                None => break,
+                Some(c) if !triple && matches!(c, '\n' | ';') => break,
+                Some(c)
+                    if c == delimiter
+                        && (!triple
+                            || (self.cursor.peek_at(1) == Some(&delimiter)
+                                && self.cursor.peek_at(2) == Some(&delimiter))) =>
+                {
+                    self.read_char();
+                    if triple {
+                        self.read_char();
+                        self.read_char();
+                    }
+                    break;
+                }
+                Some('\\') => {
+                    let escape_start = self.position.clone();
+                    self.read_char(); // consume the backslash
+                    string.push(self.read_escape(&escape_start)?);
+                }
+                Some(_) => string.push(self.read_char().unwrap()), // Unreachable
+            }
         }
 
         Ok(string)
     }
 
-    fn take_whitespace(&mut self) {
-        while self.is_whitespace() {
-            self.read_char();
+    /** Called right after the backslash of an escape sequence has been consumed. */
+    fn read_escape(&mut self, escape_start: &Pos) -> Result<char, CompilationErr> {
+        match self.read_char() {
+            Some('"') => Ok('"'),
+            Some('\'') => Ok('\''),
+            Some('`') => Ok('`'),
+            Some('\\') => Ok('\\'),
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('u') => self.read_unicode_escape(escape_start),
+            other => Err(CompilationErr {
+                kind: CompilationErrKind::InvalidEscape,
+                message: format!(
+                    "Invalid escape sequence '\\{}' at: {}:{}",
+                    other.map(String::from).unwrap_or_default(),
+                    self.position.file.as_ref().unwrap_or(&String::from("")),
+                    Span {
+                        start: escape_start.clone(),
+                        end: self.position.clone()
+                    }
+                ),
+            }),
         }
     }
-}
 
-impl Iterator for Lexer {
-    type Item = Result<(Token, Pos), CompilationErr>;
+    /** Called right after the `u` of a `\u{...}` escape has been consumed. */
+    fn read_unicode_escape(&mut self, escape_start: &Pos) -> Result<char, CompilationErr> {
+        let invalid = |this: &Self| CompilationErr {
+            kind: CompilationErrKind::InvalidEscape,
+            message: format!(
+                "Invalid \\u escape at: {}:{}",
+                this.position.file.as_ref().unwrap_or(&String::from("")),
+                Span {
+                    start: escape_start.clone(),
+                    end: this.position.clone()
+                }
+            ),
+        };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.read_char();
-        self.take_whitespace();
-        let pos = self.position.clone();
+        if self.read_char() != Some('{') {
+            return Err(invalid(self));
+        }
+
+        let mut digits = String::new();
+        loop {
+            match self.read_char() {
+                Some('}') => break,
+                Some(c) if c.is_ascii_hexdigit() => digits.push(c),
+                _ => return Err(invalid(self)),
+            }
+        }
+
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| invalid(self))
+    }
+
+
+    fn rule_operators(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        let ch = self.ch?;
+        match ch {
+            '=' => Some(Ok(Token::Assign(ch))),
+            '+' => Some(Ok(Token::Plus(ch))),
+            '-' => Some(Ok(Token::Minus(ch))),
+
+            '{' => {
+                self.push_mode(LexerMode::Code);
+                Some(Ok(Token::LCurly(ch)))
+            }
+            '}' => {
+                self.pop_mode();
+                Some(Ok(Token::RCurly(ch)))
+            }
+
+            '(' => Some(Ok(Token::LParen(ch))),
+            ')' => Some(Ok(Token::RParen(ch))),
+            _ => None,
+        }
+    }
+
+    fn rule_string(&mut self, start: &Pos) -> Option<Result<Token, CompilationErr>> {
         match self.ch {
-            Some(ch) => match ch {
-                '=' => Some(Ok((Token::Assign(ch), pos))),
-                '+' => Some(Ok((Token::Plus(ch), pos))),
-                '-' => Some(Ok((Token::Minus(ch), pos))),
-
-                '{' => Some(Ok((Token::LCurly(ch), pos))),
-                '}' => Some(Ok((Token::RCurly(ch), pos))),
-                '(' => Some(Ok((Token::LParen(ch), pos))),
-                ')' => Some(Ok((Token::RParen(ch), pos))),
-
-                '"' | '\'' | '`' => Some(if let Ok(string) = self.read_string() {
-                    Ok((Token::String(string), pos))
-                } else {
-                    Err(CompilationErr {
-                        kind: CompilationErrKind::InvalidString,
-                        message: format!(
-                            "Invalid String found at: {}:{},{}",
-                            self.position.file.as_ref().unwrap_or(&String::from("")),
-                            self.position.line,
-                            self.position.column
-                        ),
-                    })
-                }),
+            Some(delimiter @ ('"' | '\'' | '`')) => {
+                Some(self.read_string(delimiter, start).map(Token::String))
+            }
+            _ => None,
+        }
+    }
 
-                '\n' | ';' => Some(Ok((Token::EndOfLine, pos))),
-                o => {
-                    if Self::is_alphabetic(Some(&o)) {
-                        return Some(Ok((Token::Ident(self.read_ident().ok()?), pos)));
-                    }
+    fn rule_eol(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        match self.ch {
+            Some('\n') | Some(';') => Some(Ok(Token::EndOfLine)),
+            _ => None,
+        }
+    }
+
+    fn rule_ident(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        if !Self::is_alphabetic(self.ch.as_ref()) {
+            return None;
+        }
+        let starts_statement = self.at_statement_start;
+        Some(self.read_ident().map(|ident| {
+            if starts_statement && self.peeks_quote_after_whitespace() {
+                self.push_mode(LexerMode::Dialogue);
+            }
+            Token::Ident(ident)
+        }))
+    }
+
+    /** Looks past any immediately-following run of spaces/tabs for an opening quote, without consuming anything. */
+    fn peeks_quote_after_whitespace(&self) -> bool {
+        let mut ahead = 0;
+        while matches!(self.cursor.peek_at(ahead), Some(' ') | Some('\t')) {
+            ahead += 1;
+        }
+        matches!(self.cursor.peek_at(ahead), Some('"') | Some('\'') | Some('`'))
+    }
+
+    /**
+     * Trivia tokens don't count as statements, so they leave this unchanged; a
+     * `{`/`}`/newline opens a new statement, any other token is the middle of one.
+     */
+    fn update_statement_start(&mut self, token: &Token) {
+        if matches!(
+            token,
+            Token::Whitespace(_) | Token::LineComment(_) | Token::BlockComment(_)
+        ) {
+            return;
+        }
+        self.at_statement_start = matches!(
+            token,
+            Token::EndOfLine | Token::LCurly(_) | Token::RCurly(_)
+        );
+    }
+
+    fn rule_dot(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        if self.ch == Some('.') && Self::is_alphabetic(self.cursor.peek()) {
+            Some(Ok(Token::Dot))
+        } else {
+            None
+        }
+    }
 
-                    if o == '.' && Self::is_alphabetic(self.input.peek()) {
-                        return Some(Ok((Token::Dot, pos)));
+    fn rule_num(&mut self, start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        if !Self::is_num_char(self.ch.as_ref()) {
+            return None;
+        }
+        Some(match self.read_num() {
+            Ok(value) => Ok(self.read_duration_suffix(value)),
+            // An adjacent unit-looking suffix on an otherwise invalid number (`1.2.3s`)
+            // means the author meant a duration, so report that instead of InvalidNumber.
+            Err(_) if Self::is_alphabetic(self.cursor.peek()) => Err(CompilationErr {
+                kind: CompilationErrKind::InvalidDuration,
+                message: format!(
+                    "Invalid duration at: {}:{}",
+                    self.position.file.as_ref().unwrap_or(&String::from("")),
+                    Span {
+                        start: start.clone(),
+                        end: self.position.clone()
                     }
+                ),
+            }),
+            Err(err) => Err(err),
+        })
+    }
+
+    /**
+     * Called right after a number has been successfully read. Speculatively consumes
+     * the adjacent run of letters and, if it's exactly `ms`/`s`/`m`, folds it into a
+     * [`Token::Duration`]. Any other adjacent letters (`1.x`, `5apples`) aren't a unit,
+     * so the speculative read is undone with [`seek_back`](Lexer::seek_back) and the
+     * number is returned as a plain [`Token::Num`] -- the letters are left for the next
+     * rule to lex as their own `Ident` token, never turned into a lex error.
+     */
+    fn read_duration_suffix(&mut self, value: Vec<char>) -> Token {
+        let mut suffix = String::new();
+        while Self::is_alphabetic(self.cursor.peek()) {
+            if let Some(c) = self.read_char() {
+                suffix.push(c);
+            }
+        }
 
-                    if Self::is_num_char(Some(&o)) {
-                        return Some(Ok((Token::Num(self.read_num().ok()?), pos)));
+        let unit = match suffix.as_str() {
+            "ms" => DurationUnit::Ms,
+            "s" => DurationUnit::S,
+            "m" => DurationUnit::M,
+            _ => {
+                if !suffix.is_empty() {
+                    self.seek_back(suffix.chars().count());
+                }
+                return Token::Num(value);
+            }
+        };
+
+        Token::Duration { value, unit }
+    }
+
+    /**
+     * Reads everything up to the next newline/`;` as one literal [`Token::String`], no
+     * operator splitting. A dialogue line is always exactly one token wide -- the
+     * `Dialogue` mode is popped as soon as it's read, returning to `Code` for the `;`/`\n`
+     * that ends the line and whatever statement follows.
+     */
+    fn rule_dialogue_text(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        match self.ch {
+            Some('\n') | Some(';') | None => None,
+            Some(ch) => {
+                let mut text = vec![ch];
+                while !matches!(self.cursor.peek(), None | Some('\n') | Some(';')) {
+                    if let Some(c) = self.read_char() {
+                        text.push(c);
                     }
-                    Some(Ok((Token::Invalid(ch), pos)))
                 }
-            },
-            None => None,
+                self.pop_mode();
+                Some(Ok(Token::String(text)))
+            }
+        }
+    }
+
+    /** A contiguous run of spaces/tabs, emitted as a single trivia token instead of being discarded. */
+    fn rule_whitespace(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        if !self.is_whitespace() {
+            return None;
+        }
+        let mut ws = vec![self.ch.unwrap()];
+        while matches!(self.cursor.peek(), Some(' ') | Some('\t')) {
+            if let Some(c) = self.read_char() {
+                ws.push(c);
+            }
+        }
+        Some(Ok(Token::Whitespace(ws)))
+    }
+
+    /** A `#` or `//` comment up to (not including) the newline that ends it. */
+    fn rule_line_comment(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        let is_double_slash = self.ch == Some('/') && self.cursor.peek() == Some(&'/');
+        if self.ch != Some('#') && !is_double_slash {
+            return None;
+        }
+        let mut text = vec![self.ch.unwrap()];
+        if is_double_slash {
+            text.push(self.read_char().unwrap());
+        }
+        while !matches!(self.cursor.peek(), None | Some('\n')) {
+            text.push(self.read_char().unwrap());
+        }
+        Some(Ok(Token::LineComment(text)))
+    }
+
+    /** A `/* ... */` comment, including both delimiters; tolerates reaching EOF unterminated. */
+    fn rule_block_comment(&mut self, _start: &Pos) -> Option<Result<Token, CompilationErr>> {
+        if self.ch != Some('/') || self.cursor.peek() != Some(&'*') {
+            return None;
+        }
+        let mut text = vec![self.ch.unwrap(), self.read_char().unwrap()];
+        loop {
+            match self.cursor.peek().copied() {
+                None => break,
+                Some('*') if self.cursor.peek_at(1) == Some(&'/') => {
+                    text.push(self.read_char().unwrap());
+                    text.push(self.read_char().unwrap());
+                    break;
+                }
+                Some(_) => text.push(self.read_char().unwrap()),
+            }
+        }
+        Some(Ok(Token::BlockComment(text)))
+    }
+}
+
+impl Iterator for Lexer {
+    type Item = Result<(Token, Span), CompilationErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_char();
+        self.ch?;
+        let start = self.position.clone();
+        for rule in self.current_mode().rules() {
+            if let Some(result) = rule(self, &start) {
+                let end = self.position.clone();
+                return Some(result.map(|token| {
+                    self.update_statement_start(&token);
+                    (token, Span { start, end })
+                }));
+            }
         }
+        let end = self.position.clone();
+        self.at_statement_start = false;
+        Some(Ok((Token::Invalid(self.ch.unwrap()), Span { start, end })))
+    }
+}
+
+impl Lexer {
+    /**
+     * Adapts the raw, full-fidelity token stream -- which includes
+     * [`Token::Whitespace`], [`Token::LineComment`] and [`Token::BlockComment`]
+     * -- by filtering those trivia tokens out. This is what normal compilation
+     * wants; the raw `Lexer` iterator itself keeps every byte of input
+     * represented, which is what a formatter or syntax highlighter needs.
+     */
+    pub fn iter_skip_trivia(self) -> impl Iterator<Item = Result<(Token, Span), CompilationErr>> {
+        self.filter(|item| {
+            !matches!(
+                item,
+                Ok((
+                    Token::Whitespace(_) | Token::LineComment(_) | Token::BlockComment(_),
+                    _
+                ))
+            )
+        })
     }
 }
 
@@ -309,13 +873,13 @@ mod test {
             lex.next().unwrap()?.0,
             Token::Ident(vec!['h', 'e', 'l', 'l', 'o'])
         );
-        let mut lex = Lexer::new("   \nhello");
+        let mut lex = Lexer::new("   \nhello").iter_skip_trivia();
         lex.next();
         assert_eq!(
             lex.next().unwrap()?.0,
             Token::Ident(vec!['h', 'e', 'l', 'l', 'o'])
         );
-        let mut lex = Lexer::new("     h1");
+        let mut lex = Lexer::new("     h1").iter_skip_trivia();
         assert_eq!(lex.next().unwrap()?.0, Token::Ident(vec!['h', '1']));
         Ok(())
     }
@@ -332,22 +896,90 @@ mod test {
     }
 
     #[test]
-    #[should_panic]
+    fn duration_suffix_is_folded_into_a_single_token() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("1s");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::Duration {
+                value: vec!['1'],
+                unit: DurationUnit::S
+            }
+        );
+        let mut lex = Lexer::new("250ms");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::Duration {
+                value: vec!['2', '5', '0'],
+                unit: DurationUnit::Ms
+            }
+        );
+        let mut lex = Lexer::new("1.5m");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::Duration {
+                value: vec!['1', '.', '5'],
+                unit: DurationUnit::M
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn number_without_adjacent_unit_stays_a_plain_num() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("1 s");
+        assert_eq!(lex.next().unwrap()?.0, Token::Num(vec!['1']));
+        Ok(())
+    }
+
+    #[test]
+    fn number_followed_by_a_non_unit_ident_lexes_as_two_tokens() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("1.x");
+        assert_eq!(lex.next().unwrap()?.0, Token::Num(vec!['1', '.']));
+        assert_eq!(lex.next().unwrap()?.0, Token::Ident(vec!['x']));
+
+        let mut lex = Lexer::new("1.foo");
+        assert_eq!(lex.next().unwrap()?.0, Token::Num(vec!['1', '.']));
+        assert_eq!(lex.next().unwrap()?.0, Token::Ident(vec!['f', 'o', 'o']));
+
+        let mut lex = Lexer::new("5apples");
+        assert_eq!(lex.next().unwrap()?.0, Token::Num(vec!['5']));
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::Ident(vec!['a', 'p', 'p', 'l', 'e', 's'])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_number_with_a_unit_suffix_is_an_invalid_duration() {
+        let mut lex = Lexer::new("1.2.3s");
+        assert_eq!(
+            lex.next().unwrap().unwrap_err().kind,
+            CompilationErrKind::InvalidDuration
+        );
+    }
+
+    #[test]
     fn incorrect_num_dots() {
         let mut lex = Lexer::new("..");
-        _ = lex.next().unwrap();
+        assert_eq!(
+            lex.next().unwrap().unwrap_err().kind,
+            CompilationErrKind::InvalidNumber
+        );
     }
 
     #[test]
-    #[should_panic]
     fn incorrect_num() {
         let mut lex = Lexer::new("0000.10.0");
-        let _ = lex.next().unwrap();
+        assert_eq!(
+            lex.next().unwrap().unwrap_err().kind,
+            CompilationErrKind::InvalidNumber
+        );
     }
 
     #[test]
     fn token_chain() {
-        let mut lex = Lexer::new("a b c d");
+        let mut lex = Lexer::new("a b c d").iter_skip_trivia();
         lex.next();
         lex.next();
         lex.next();
@@ -380,13 +1012,200 @@ mod test {
         assert_eq!(Token::EndOfLine, lex.next().unwrap().unwrap().0);
         assert_eq!(Token::String(vec!['b']), lex.next().unwrap().unwrap().0);
 
-        let mut lex = Lexer::new("\"a\" b");
+        let mut lex = Lexer::new("\"a\" b").iter_skip_trivia();
         assert_eq!(Token::String(vec!['a']), lex.next().unwrap().unwrap().0);
         assert_eq!(Token::Ident(vec!['b']), lex.next().unwrap().unwrap().0);
     }
 
-    //#[test]
-    fn _complex() {
+    #[test]
+    fn curly_braces_push_and_pop_code_mode() {
+        let mut lex = Lexer::new("{ a }");
+        assert_eq!(lex.next().unwrap().unwrap().0, Token::LCurly('{'));
+        assert_eq!(lex.mode_stack, vec![LexerMode::Code, LexerMode::Code]);
+        lex.next(); // ' '
+        lex.next(); // 'a'
+        lex.next(); // ' '
+        assert_eq!(lex.next().unwrap().unwrap().0, Token::RCurly('}'));
+        assert_eq!(lex.mode_stack, vec![LexerMode::Code]);
+    }
+
+    #[test]
+    fn dialogue_mode_reads_whole_line_as_one_string() {
+        let mut lex = Lexer::new("What a fine day, Crab!\nnext");
+        lex.push_mode(LexerMode::Dialogue);
+        assert_eq!(
+            lex.next().unwrap().unwrap().0,
+            Token::String("What a fine day, Crab!".chars().collect())
+        );
+        // Reading the line popped `Dialogue`, so the newline is lexed by `Code` again.
+        assert_eq!(lex.next().unwrap().unwrap().0, Token::EndOfLine);
+        assert_eq!(lex.pop_mode(), None);
+        assert_eq!(lex.next().unwrap().unwrap().0, Token::Ident(vec!['n', 'e', 'x', 't']));
+    }
+
+    #[test]
+    fn span_covers_the_whole_multi_char_token() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("hello");
+        let (token, span) = lex.next().unwrap()?;
+        assert_eq!(token, Token::Ident(vec!['h', 'e', 'l', 'l', 'o']));
+        assert_ne!(span.start, span.end);
+        Ok(())
+    }
+
+    #[test]
+    fn pop_mode_never_removes_the_base_code_mode() {
+        let mut lex = Lexer::new("");
+        assert_eq!(lex.pop_mode(), None);
+    }
+
+    #[test]
+    fn cursor_peek_is_o1_and_does_not_consume() {
+        let mut c = Cursor::new("ab");
+        assert_eq!(c.peek(), Some(&'a'));
+        assert_eq!(c.peek(), Some(&'a'));
+        assert_eq!(c.next(), Some('a'));
+        assert_eq!(c.peek(), Some(&'b'));
+    }
+
+    #[test]
+    fn cursor_seek_back_and_forward_replay_history() {
+        let mut c = Cursor::new("abc");
+        assert_eq!(c.next(), Some('a'));
+        assert_eq!(c.next(), Some('b'));
+        c.seek_back(2);
+        assert_eq!(c.next(), Some('a'));
+        assert_eq!(c.next(), Some('b'));
+        c.seek_forward(1); // already back at the live edge, a no-op
+        assert_eq!(c.next(), Some('c'));
+        assert_eq!(c.next(), None);
+    }
+
+    #[test]
+    fn cursor_restores_line_and_column_across_a_newline_boundary() {
+        let mut c = Cursor::new("ab\ncd");
+        assert_eq!(c.next(), Some('a'));
+        assert_eq!(c.next(), Some('b'));
+        assert_eq!(c.next(), Some('\n'));
+        assert_eq!(c.line_and_column(), (1, 0)); // about to read 'c', first column of line 1
+
+        c.seek_back(1); // rewind across the newline we just consumed
+        assert_eq!(c.line_and_column(), (0, 2)); // back at the newline, end of line 0
+        assert_eq!(c.peek(), Some(&'\n'));
+
+        c.seek_forward(1);
+        assert_eq!(c.line_and_column(), (1, 0));
+        assert_eq!(c.next(), Some('c'));
+        assert_eq!(c.next(), Some('d'));
+        assert_eq!(c.next(), None);
+    }
+
+    #[test]
+    fn string_escapes_are_decoded() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new(r#""a\"b\n\t\\c""#);
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::String(vec!['a', '"', 'b', '\n', '\t', '\\', 'c'])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn string_unicode_escape_is_decoded() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new(r#""\u{1F980}""#);
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::String(vec!['\u{1F980}'])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_escape_is_an_error() {
+        let mut lex = Lexer::new(r#""a\qb""#);
+        assert_eq!(
+            lex.next().unwrap().unwrap_err().kind,
+            CompilationErrKind::InvalidEscape
+        );
+    }
+
+    #[test]
+    fn triple_quoted_string_spans_multiple_lines() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("\"\"\"first\nsecond\"\"\"");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::String("first\nsecond".chars().collect())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn triple_quoted_backtick_string_spans_multiple_lines() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("```first\nsecond```");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::String("first\nsecond".chars().collect())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unterminated_triple_quoted_string_is_an_error() {
+        let mut lex = Lexer::new("\"\"\"never closed");
+        assert_eq!(
+            lex.next().unwrap().unwrap_err().kind,
+            CompilationErrKind::UnterminatedString
+        );
+    }
+
+    #[test]
+    fn whitespace_and_comments_are_kept_as_trivia_tokens() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("  # a comment\na");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::Whitespace(vec![' ', ' '])
+        );
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::LineComment("# a comment".chars().collect())
+        );
+        assert_eq!(lex.next().unwrap()?.0, Token::EndOfLine);
+        assert_eq!(lex.next().unwrap()?.0, Token::Ident(vec!['a']));
+        Ok(())
+    }
+
+    #[test]
+    fn double_slash_line_comment_is_recognised() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("// hi\n");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::LineComment("// hi".chars().collect())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn block_comment_is_recognised() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("/* a\nb */c");
+        assert_eq!(
+            lex.next().unwrap()?.0,
+            Token::BlockComment("/* a\nb */".chars().collect())
+        );
+        assert_eq!(lex.next().unwrap()?.0, Token::Ident(vec!['c']));
+        Ok(())
+    }
+
+    #[test]
+    fn iter_skip_trivia_filters_whitespace_and_comments() -> Result<(), CompilationErr> {
+        let mut lex = Lexer::new("a  # comment\nb").iter_skip_trivia();
+        assert_eq!(lex.next().unwrap()?.0, Token::Ident(vec!['a']));
+        assert_eq!(lex.next().unwrap()?.0, Token::EndOfLine);
+        assert_eq!(lex.next().unwrap()?.0, Token::Ident(vec!['b']));
+        assert_eq!(lex.next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn _complex() -> Result<(), CompilationErr> {
         let lex = Lexer::new(
             r#"c = Character "Crab", "./sprites/crab"
 c_idle = Animation {
@@ -396,10 +1215,54 @@ c_idle = Animation {
 }
 c_idle run
 c "What a fine day""#,
-        );
-        panic!(
-            "{:?}",
-            lex.collect::<Vec<Result<(Token, Pos), CompilationErr>>>()
         )
+        .iter_skip_trivia();
+        let toks = lex
+            .map(|r| r.map(|(token, _)| token))
+            .collect::<Result<Vec<Token>, CompilationErr>>()?;
+
+        assert_eq!(
+            toks,
+            vec![
+                Token::Ident(vec!['c']),
+                Token::Assign('='),
+                Token::Ident("Character".chars().collect()),
+                Token::String("Crab".chars().collect()),
+                // Not part of this backlog: the grammar has no comma operator yet.
+                Token::Invalid(','),
+                Token::String("./sprites/crab".chars().collect()),
+                Token::EndOfLine,
+                Token::Ident("c_idle".chars().collect()),
+                Token::Assign('='),
+                Token::Ident("Animation".chars().collect()),
+                Token::LCurly('{'),
+                Token::EndOfLine,
+                Token::Ident(vec!['c']),
+                Token::Ident("show".chars().collect()),
+                Token::Ident("left".chars().collect()),
+                Token::EndOfLine,
+                Token::Ident("wait".chars().collect()),
+                Token::Duration {
+                    value: vec!['1'],
+                    unit: DurationUnit::S
+                },
+                Token::EndOfLine,
+                Token::Ident(vec!['c']),
+                Token::Ident("show".chars().collect()),
+                Token::Ident("right".chars().collect()),
+                Token::EndOfLine,
+                Token::RCurly('}'),
+                Token::EndOfLine,
+                Token::Ident("c_idle".chars().collect()),
+                Token::Ident("run".chars().collect()),
+                Token::EndOfLine,
+                // A bare identifier at the start of a statement directly followed by a
+                // quote enters `Dialogue` mode for the rest of the line: the quotes and
+                // leading space are kept as literal prose, not stripped like a `Code` string.
+                Token::Ident(vec!['c']),
+                Token::String(" \"What a fine day\"".chars().collect()),
+            ]
+        );
+        Ok(())
     }
 }